@@ -0,0 +1,542 @@
+// Sonata
+// Copyright (c) 2019 The Sonata Project Developers.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An implementation of `MetadataReader` for the ID3v2 tag format, as found prepended to MP3 and
+//! other streams.
+
+use std::io::Read;
+
+use crate::errors::{decode_error, Result};
+use crate::io::MediaSourceStream;
+
+use super::{
+    Limit, Metadata, MetadataBuilder, MetadataOptions, MetadataReader, StandardTagKey,
+    StandardVisualKey, Tag, Visual,
+};
+
+/// The ID3v2 tag header, as read from the first 10 bytes of the tag.
+struct Header {
+    major_version: u8,
+    unsynchronized: bool,
+    extended_header: bool,
+    size: u32,
+}
+
+/// Decodes a 28-bit syncsafe integer, as used for the tag size in the header, and for frame
+/// sizes in ID3v2.4, into a regular `u32`.
+fn syncsafe_u32(bytes: [u8; 4]) -> u32 {
+    (u32::from(bytes[0]) << 21)
+        | (u32::from(bytes[1]) << 14)
+        | (u32::from(bytes[2]) << 7)
+        | u32::from(bytes[3])
+}
+
+fn read_header(reader: &mut MediaSourceStream) -> Result<Header> {
+    let mut buf = [0u8; 10];
+    reader.read_exact(&mut buf)?;
+
+    if &buf[0..3] != b"ID3" {
+        return decode_error("id3v2: missing magic number");
+    }
+
+    let major_version = buf[3];
+    let flags = buf[5];
+
+    Ok(Header {
+        major_version,
+        unsynchronized: flags & 0x80 != 0,
+        extended_header: flags & 0x40 != 0,
+        size: syncsafe_u32([buf[6], buf[7], buf[8], buf[9]]),
+    })
+}
+
+/// Replaces every `0xff 0x00` byte pair with a single `0xff` byte, undoing the unsynchronization
+/// scheme applied to the tag body.
+fn remove_unsynchronization(buf: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(buf.len());
+
+    let mut i = 0;
+    while i < buf.len() {
+        out.push(buf[i]);
+        if buf[i] == 0xff && i + 1 < buf.len() && buf[i + 1] == 0x00 {
+            i += 1;
+        }
+        i += 1;
+    }
+
+    out
+}
+
+/// Maps a 4-character (ID3v2.3/2.4) or 3-character (ID3v2.2) frame identifier to a
+/// `StandardTagKey`, if a mapping is known.
+fn std_tag_key_for_id(id: &str) -> Option<StandardTagKey> {
+    Some(match id {
+        "TIT2" | "TT2" => StandardTagKey::TrackTitle,
+        "TPE1" | "TP1" => StandardTagKey::Artist,
+        "TPE2" | "TP2" => StandardTagKey::AlbumArtist,
+        "TALB" | "TAL" => StandardTagKey::Album,
+        "TRCK" | "TRK" => StandardTagKey::TrackNumber,
+        "TPOS" | "TPA" => StandardTagKey::DiscNumber,
+        "TCON" | "TCO" => StandardTagKey::Genre,
+        "TYER" | "TYE" | "TDRC" => StandardTagKey::Date,
+        "TCOM" | "TCM" => StandardTagKey::Composer,
+        "TCOP" | "TCR" => StandardTagKey::Copyright,
+        "TENC" | "TEN" => StandardTagKey::EncodedBy,
+        "TBPM" | "TBP" => StandardTagKey::Bpm,
+        "TPUB" | "TPB" => StandardTagKey::Label,
+        "TCMP" => StandardTagKey::Compilation,
+        "USLT" | "ULT" => StandardTagKey::Lyrics,
+        "COMM" | "COM" => StandardTagKey::Comment,
+        _ => return None,
+    })
+}
+
+/// Maps an APIC picture type byte to a `StandardVisualKey`.
+fn std_visual_key_for_picture_type(picture_type: u8) -> Option<StandardVisualKey> {
+    Some(match picture_type {
+        0x01 => StandardVisualKey::FileIcon,
+        0x02 => StandardVisualKey::OtherIcon,
+        0x03 => StandardVisualKey::FrontCover,
+        0x04 => StandardVisualKey::BackCover,
+        0x05 => StandardVisualKey::Leaflet,
+        0x06 => StandardVisualKey::Media,
+        0x07 => StandardVisualKey::LeadArtistPerformerSoloist,
+        0x08 => StandardVisualKey::ArtistPerformer,
+        0x09 => StandardVisualKey::Conductor,
+        0x0a => StandardVisualKey::BandOrchestra,
+        0x0b => StandardVisualKey::Composer,
+        0x0c => StandardVisualKey::Lyricist,
+        0x0d => StandardVisualKey::RecordingLocation,
+        0x0e => StandardVisualKey::RecordingSession,
+        0x0f => StandardVisualKey::Performance,
+        0x12 => StandardVisualKey::Illustration,
+        0x13 => StandardVisualKey::BandArtistLogo,
+        0x14 => StandardVisualKey::PublisherStudioLogo,
+        _ => return None,
+    })
+}
+
+/// Decodes a text frame payload (encoding byte followed by the encoded string) into a `String`,
+/// trimming any trailing nul terminator.
+fn decode_text(buf: &[u8]) -> String {
+    if buf.is_empty() {
+        return String::new();
+    }
+
+    let (encoding, data) = (buf[0], &buf[1..]);
+
+    let text = match encoding {
+        // ISO-8859-1
+        0 => data.iter().map(|&b| b as char).collect::<String>(),
+        // UTF-16 with a byte-order-mark.
+        1 => decode_utf16(data, None),
+        // UTF-16BE, no byte-order-mark.
+        2 => decode_utf16(data, Some(false)),
+        // UTF-8
+        3 => String::from_utf8_lossy(data).into_owned(),
+        _ => String::from_utf8_lossy(data).into_owned(),
+    };
+
+    text.trim_end_matches('\u{0}').to_string()
+}
+
+/// Decodes a UTF-16 byte buffer. If `is_little_endian` is `None`, the byte order is determined
+/// from a leading byte-order-mark, defaulting to little-endian if one is not present.
+fn decode_utf16(data: &[u8], is_little_endian: Option<bool>) -> String {
+    let (little_endian, data) = match is_little_endian {
+        Some(le) => (le, data),
+        None => {
+            if data.len() >= 2 && data[0] == 0xff && data[1] == 0xfe {
+                (true, &data[2..])
+            } else if data.len() >= 2 && data[0] == 0xfe && data[1] == 0xff {
+                (false, &data[2..])
+            } else {
+                (true, data)
+            }
+        }
+    };
+
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|pair| {
+            if little_endian {
+                u16::from_le_bytes([pair[0], pair[1]])
+            } else {
+                u16::from_be_bytes([pair[0], pair[1]])
+            }
+        })
+        .collect();
+
+    String::from_utf16_lossy(&units)
+}
+
+/// Reads a single frame starting at the head of `buf`, adding any recognized tag or visual to
+/// `builder`. Returns the total number of bytes consumed by the frame (header + payload), or
+/// `None` if `buf` does not contain a full frame header (i.e., padding was reached).
+fn read_frame(
+    buf: &[u8],
+    major_version: u8,
+    options: &MetadataOptions,
+    builder: &mut MetadataBuilder,
+) -> Result<Option<usize>> {
+    let id_len = if major_version == 2 { 3 } else { 4 };
+    let header_len = if major_version == 2 { 6 } else { 10 };
+
+    if buf.len() < header_len || buf[0] == 0 {
+        return Ok(None);
+    }
+
+    let id = String::from_utf8_lossy(&buf[0..id_len]).into_owned();
+
+    let size = if major_version == 2 {
+        (u32::from(buf[3]) << 16) | (u32::from(buf[4]) << 8) | u32::from(buf[5])
+    } else if major_version == 4 {
+        syncsafe_u32([buf[4], buf[5], buf[6], buf[7]])
+    } else {
+        u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]])
+    };
+
+    let consumed = header_len + size as usize;
+
+    if buf.len() < consumed {
+        return decode_error("id3v2: frame size exceeds tag size");
+    }
+
+    let payload = &buf[header_len..consumed];
+
+    if id == "APIC" || id == "PIC" {
+        read_apic_frame(payload, major_version, options, builder)?;
+    } else if id == "TXXX" || id == "TXX" {
+        read_txxx_frame(payload, options, builder)?;
+    } else if id.starts_with('T') {
+        read_text_frame(&id, payload, options, builder)?;
+    }
+
+    Ok(Some(consumed))
+}
+
+fn check_limit(limit: &Limit, actual: usize, default: usize) -> bool {
+    match limit.limit_or_default(default) {
+        Some(max) => actual <= max,
+        None => true,
+    }
+}
+
+fn read_text_frame(
+    id: &str,
+    payload: &[u8],
+    options: &MetadataOptions,
+    builder: &mut MetadataBuilder,
+) -> Result<()> {
+    if !check_limit(&options.limit_metadata_bytes, payload.len(), 1 * 1024) {
+        return Ok(());
+    }
+
+    let value = decode_text(payload);
+    builder.add_tag(Tag::new(std_tag_key_for_id(id), id, &value));
+
+    Ok(())
+}
+
+fn read_txxx_frame(
+    payload: &[u8],
+    options: &MetadataOptions,
+    builder: &mut MetadataBuilder,
+) -> Result<()> {
+    if !check_limit(&options.limit_metadata_bytes, payload.len(), 1 * 1024) {
+        return Ok(());
+    }
+
+    if payload.is_empty() {
+        return Ok(());
+    }
+
+    // A TXXX frame is an encoding byte, a nul-terminated description, then the value, both in
+    // the frame's text encoding.
+    let text = decode_text(payload);
+    let mut parts = text.splitn(2, '\u{0}');
+    let key = parts.next().unwrap_or_default();
+    let value = parts.next().unwrap_or_default();
+
+    builder.add_tag(Tag::new(None, key, value));
+
+    Ok(())
+}
+
+fn read_apic_frame(
+    payload: &[u8],
+    major_version: u8,
+    options: &MetadataOptions,
+    builder: &mut MetadataBuilder,
+) -> Result<()> {
+    if payload.is_empty() {
+        return Ok(());
+    }
+
+    let encoding = payload[0];
+    let mut pos = 1;
+
+    let media_type = if major_version == 2 {
+        // A 3-character image format code (e.g., "JPG", "PNG") rather than a MIME type string.
+        if payload.len() < pos + 3 {
+            return decode_error("id3v2: truncated PIC frame");
+        }
+        let fmt = String::from_utf8_lossy(&payload[pos..pos + 3]).into_owned();
+        pos += 3;
+        match fmt.as_str() {
+            "PNG" => "image/png".to_string(),
+            "JPG" => "image/jpeg".to_string(),
+            other => format!("image/{}", other.to_lowercase()),
+        }
+    } else {
+        let end = payload[pos..]
+            .iter()
+            .position(|&b| b == 0)
+            .map(|p| pos + p)
+            .unwrap_or(payload.len());
+        let mime = String::from_utf8_lossy(&payload[pos..end]).into_owned();
+        pos = end + 1;
+        mime
+    };
+
+    if pos >= payload.len() {
+        return decode_error("id3v2: truncated APIC frame");
+    }
+
+    let picture_type = payload[pos];
+    pos += 1;
+
+    let desc_start = pos;
+    let desc_end = match encoding {
+        1 | 2 => {
+            // UTF-16 descriptions are terminated by a 2-byte nul.
+            let mut i = desc_start;
+            while i + 1 < payload.len() && !(payload[i] == 0 && payload[i + 1] == 0) {
+                i += 2;
+            }
+            i.min(payload.len())
+        }
+        _ => payload[desc_start..]
+            .iter()
+            .position(|&b| b == 0)
+            .map(|p| desc_start + p)
+            .unwrap_or(payload.len()),
+    };
+
+    let mut desc_buf = vec![encoding];
+    desc_buf.extend_from_slice(&payload[desc_start..desc_end]);
+    let description = decode_text(&desc_buf);
+
+    let data_start = match encoding {
+        1 | 2 => desc_end + 2,
+        _ => desc_end + 1,
+    }
+    .min(payload.len());
+
+    let data = &payload[data_start..];
+
+    if !check_limit(&options.limit_visual_bytes, data.len(), 1024 * 1024) {
+        return Ok(());
+    }
+
+    let mut visual = Visual {
+        media_type,
+        dimensions: None,
+        bits_per_pixel: None,
+        color_mode: None,
+        usage: std_visual_key_for_picture_type(picture_type),
+        tags: if description.is_empty() {
+            Vec::new()
+        } else {
+            vec![Tag::new(None, "description", &description)]
+        },
+        data: data.to_vec().into_boxed_slice(),
+    };
+
+    if options.probe_visual_dimensions {
+        super::probe_visual(&mut visual);
+    }
+
+    builder.add_visual(visual);
+
+    Ok(())
+}
+
+/// A `MetadataReader` implementation for the ID3v2 tag format.
+pub struct Id3v2Reader {
+    options: MetadataOptions,
+}
+
+impl MetadataReader for Id3v2Reader {
+    fn new(options: &MetadataOptions) -> Self {
+        Id3v2Reader { options: *options }
+    }
+
+    fn read_all(&mut self, reader: &mut MediaSourceStream) -> Result<Metadata> {
+        let header = read_header(reader)?;
+
+        // `header.size` is an attacker-controlled 28-bit syncsafe integer (up to ~256 MiB) read
+        // straight from the stream. Check it against the limit before allocating and reading the
+        // whole tag body, rather than only checking each frame's payload afterward, by which point
+        // the oversized allocation and read has already happened.
+        if !check_limit(&self.options.limit_metadata_bytes, header.size as usize, 16 * 1024 * 1024) {
+            return decode_error("id3v2: tag size exceeds the configured limit");
+        }
+
+        let mut body = vec![0u8; header.size as usize];
+        reader.read_exact(&mut body)?;
+
+        if header.unsynchronized {
+            body = remove_unsynchronization(&body);
+        }
+
+        let mut pos = 0;
+
+        // Skip over the extended header, if present. Its size is itself a syncsafe u32.
+        if header.extended_header {
+            if body.len() < pos + 4 {
+                return decode_error("id3v2: truncated extended header");
+            }
+            let ext_size = syncsafe_u32([body[pos], body[pos + 1], body[pos + 2], body[pos + 3]]);
+            pos += ext_size as usize;
+        }
+
+        let mut builder = MetadataBuilder::new();
+
+        while pos < body.len() {
+            match read_frame(&body[pos..], header.major_version, &self.options, &mut builder)? {
+                Some(consumed) if consumed > 0 => pos += consumed,
+                _ => break,
+            }
+        }
+
+        Ok(builder.metadata())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Seek, SeekFrom};
+
+    use crate::io::MediaSource;
+
+    use super::*;
+
+    struct TestSource(Cursor<Vec<u8>>);
+
+    impl Read for TestSource {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Seek for TestSource {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    impl MediaSource for TestSource {
+        fn is_seekable(&self) -> bool {
+            true
+        }
+
+        fn byte_len(&self) -> Option<u64> {
+            Some(self.0.get_ref().len() as u64)
+        }
+    }
+
+    fn stream(bytes: Vec<u8>) -> MediaSourceStream {
+        MediaSourceStream::new(Box::new(TestSource(Cursor::new(bytes))), Default::default())
+    }
+
+    fn encode_syncsafe(value: u32) -> [u8; 4] {
+        [
+            ((value >> 21) & 0x7f) as u8,
+            ((value >> 14) & 0x7f) as u8,
+            ((value >> 7) & 0x7f) as u8,
+            (value & 0x7f) as u8,
+        ]
+    }
+
+    #[test]
+    fn missing_magic_is_a_decode_error_not_a_panic() {
+        let mut reader = stream(vec![b'X', b'Y', b'Z', 3, 0, 0, 0, 0, 0, 0]);
+        assert!(read_header(&mut reader).is_err());
+    }
+
+    #[test]
+    fn frame_size_exceeding_tag_size_is_a_decode_error() {
+        let mut builder = MetadataBuilder::new();
+        let options = MetadataOptions::default();
+
+        // A TIT2 frame claiming a 100-byte payload, followed by only 2 payload bytes.
+        let mut buf = b"TIT2".to_vec();
+        buf.extend_from_slice(&100u32.to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&[0, 0]);
+
+        assert!(read_frame(&buf, 3, &options, &mut builder).is_err());
+    }
+
+    #[test]
+    fn truncated_utf16_apic_description_does_not_panic() {
+        let mut builder = MetadataBuilder::new();
+        let options = MetadataOptions::default();
+
+        // encoding=1 (UTF-16+BOM), MIME "image/png\0", picture type, then a description that
+        // never reaches its closing 2-byte nul before the payload ends.
+        let mut payload = vec![1];
+        payload.extend_from_slice(b"image/png\0");
+        payload.push(0x03);
+        payload.extend_from_slice(&[0xff, 0xfe, b'A', 0]);
+
+        assert!(read_apic_frame(&payload, 3, &options, &mut builder).is_ok());
+    }
+
+    #[test]
+    fn read_all_parses_a_minimal_v23_tag() {
+        let mut frame_payload = vec![3]; // UTF-8 encoding.
+        frame_payload.extend_from_slice(b"Hello");
+
+        let mut frame = b"TIT2".to_vec();
+        frame.extend_from_slice(&(frame_payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]); // Flags.
+        frame.extend_from_slice(&frame_payload);
+
+        let mut tag = b"ID3".to_vec();
+        tag.extend_from_slice(&[3, 0, 0]); // Major version, minor version, flags.
+        tag.extend_from_slice(&encode_syncsafe(frame.len() as u32));
+        tag.extend_from_slice(&frame);
+
+        let mut reader = stream(tag);
+        let mut id3 = Id3v2Reader::new(&MetadataOptions::default());
+
+        let metadata = id3.read_all(&mut reader).expect("valid id3v2 tag");
+        assert_eq!(metadata.tags().len(), 1);
+        assert_eq!(metadata.tags()[0].value, "Hello");
+        assert!(matches!(metadata.tags()[0].std_key, Some(StandardTagKey::TrackTitle)));
+    }
+
+    #[test]
+    fn oversized_tag_size_is_rejected_before_allocating_the_body() {
+        // A header claiming a tag size far larger than the configured limit. If this were
+        // allocated and read before being checked, it would be an easy, zero-allocation-on-the-
+        // attacker's-side memory exhaustion vector; here it must be rejected from the 10-byte
+        // header alone, with no further bytes required in the stream.
+        let mut tag = b"ID3".to_vec();
+        tag.extend_from_slice(&[3, 0, 0]);
+        tag.extend_from_slice(&encode_syncsafe(200 * 1024 * 1024));
+
+        let mut reader = stream(tag);
+        let mut options = MetadataOptions::default();
+        options.limit_metadata_bytes = Limit::Maximum(1024);
+        let mut id3 = Id3v2Reader::new(&options);
+
+        assert!(id3.read_all(&mut reader).is_err());
+    }
+}
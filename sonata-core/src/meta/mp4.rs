@@ -0,0 +1,463 @@
+// Sonata
+// Copyright (c) 2019 The Sonata Project Developers.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An implementation of `MetadataReader` for the iTunes-style `ilst` metadata atoms found in the
+//! `moov.udta.meta` box of ISO Base Media File Format (MP4/M4A) streams.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::errors::{decode_error, Error, Result};
+use crate::io::MediaSourceStream;
+
+use super::{
+    Metadata, MetadataBuilder, MetadataOptions, MetadataReader, StandardTagKey, StandardVisualKey, Tag, Visual,
+};
+
+/// A single ISO-BMFF box header: its 4-character code and the byte range of its body (the bytes
+/// following the header, up to the end of the box, including any nested boxes).
+struct BoxHeader {
+    name: [u8; 4],
+    /// The offset, in the stream, of the first byte following this box (i.e. where the next
+    /// sibling box begins).
+    end: u64,
+    /// The offset, in the stream, of the first byte of this box's body.
+    body_start: u64,
+}
+
+fn read_box_header(reader: &mut MediaSourceStream) -> Result<BoxHeader> {
+    let start = reader.seek(SeekFrom::Current(0))?;
+
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+
+    let mut size = u64::from(u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]));
+    let name = [buf[4], buf[5], buf[6], buf[7]];
+
+    let mut body_start = start + 8;
+
+    if size == 1 {
+        let mut large = [0u8; 8];
+        reader.read_exact(&mut large)?;
+        size = u64::from_be_bytes(large);
+        body_start += 8;
+    } else if size == 0 {
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(start))?;
+        size = len - start;
+    }
+
+    let end = start + size;
+
+    // A box's declared size must cover at least its own header (e.g. a 64-bit `largesize` of `0`
+    // with an ordinary `size == 1`). Without this check, every caller seeks to `end` when skipping
+    // over a non-matching box, which here would seek backward to at or before `start`, sending
+    // `find_child_box`'s scan loop into an infinite cycle over the same bytes.
+    if end < body_start {
+        return decode_error("mp4: box size is smaller than its own header");
+    }
+
+    Ok(BoxHeader { name, end, body_start })
+}
+
+/// Descends into `parent`'s children, seeking to and returning the header of the first child box
+/// named `name`. The stream position is left at the start of that child's body.
+fn find_child_box(reader: &mut MediaSourceStream, parent_end: u64, name: &[u8; 4]) -> Result<Option<BoxHeader>> {
+    loop {
+        let pos = reader.seek(SeekFrom::Current(0))?;
+        if pos >= parent_end {
+            return Ok(None);
+        }
+
+        let header = read_box_header(reader)?;
+
+        if &header.name == name {
+            reader.seek(SeekFrom::Start(header.body_start))?;
+            return Ok(Some(header));
+        }
+
+        reader.seek(SeekFrom::Start(header.end))?;
+    }
+}
+
+/// Maps an `ilst` atom's 4cc key, or an `mdta` key name, to a `StandardTagKey`, if a mapping is
+/// known.
+fn std_tag_key_for_name(name: &str) -> Option<StandardTagKey> {
+    Some(match name {
+        "\u{a9}nam" => StandardTagKey::TrackTitle,
+        "\u{a9}ART" => StandardTagKey::Artist,
+        "aART" => StandardTagKey::AlbumArtist,
+        "\u{a9}alb" => StandardTagKey::Album,
+        "\u{a9}day" => StandardTagKey::Date,
+        "\u{a9}wrt" => StandardTagKey::Composer,
+        "\u{a9}cmt" => StandardTagKey::Comment,
+        "\u{a9}gen" | "gnre" => StandardTagKey::Genre,
+        "cprt" => StandardTagKey::Copyright,
+        "\u{a9}too" => StandardTagKey::Encoder,
+        "\u{a9}lyr" => StandardTagKey::Lyrics,
+        "cpil" => StandardTagKey::Compilation,
+        _ => return None,
+    })
+}
+
+/// A `data` atom's payload: the type indicator, and the raw bytes following the 8-byte
+/// type/locale header.
+struct DataAtom {
+    type_indicator: u32,
+    payload: Vec<u8>,
+}
+
+/// Reads a `data` box nested directly under an `ilst` entry, if one is present at the current
+/// position.
+fn read_data_atom(reader: &mut MediaSourceStream, end: u64, options: &MetadataOptions) -> Result<Option<DataAtom>> {
+    let header = match find_child_box(reader, end, b"data")? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+
+    let mut type_locale = [0u8; 8];
+    reader.read_exact(&mut type_locale)?;
+
+    let type_indicator = u32::from_be_bytes([type_locale[0], type_locale[1], type_locale[2], type_locale[3]]);
+
+    // `header.body_start + 8` is the first byte of the payload, following the type/locale fields
+    // just read above. A `data` box smaller than that (e.g. a malformed `size == 9`) has no room
+    // for them at all.
+    if header.end < header.body_start + 8 {
+        return decode_error("mp4: data box too small for type/locale fields");
+    }
+
+    let payload_len = (header.end - (header.body_start + 8)) as usize;
+
+    // Image payloads (`covr`) are bounded by the visual limit, everything else by the metadata
+    // limit.
+    let limit = match type_indicator {
+        13 | 14 => options.limit_visual_bytes.limit_or_default(4 * 1024 * 1024),
+        _ => options.limit_metadata_bytes.limit_or_default(8 * 1024),
+    };
+
+    if limit.map(|max| payload_len > max).unwrap_or(false) {
+        reader.seek(SeekFrom::Start(header.end))?;
+        return Ok(None);
+    }
+
+    let mut payload = vec![0u8; payload_len];
+    reader.read_exact(&mut payload)?;
+
+    reader.seek(SeekFrom::Start(header.end))?;
+
+    Ok(Some(DataAtom { type_indicator, payload }))
+}
+
+/// Decodes a packed current/total pair (`trkn`/`disk` atoms), which is stored as a
+/// big-endian `u16` reserved field, followed by the current and total `u16` values.
+fn decode_pair(payload: &[u8]) -> (Option<u16>, Option<u16>) {
+    let current = if payload.len() >= 4 { Some(u16::from_be_bytes([payload[2], payload[3]])) } else { None };
+    let total = if payload.len() >= 6 { Some(u16::from_be_bytes([payload[4], payload[5]])) } else { None };
+    (current, total)
+}
+
+fn media_type_for_indicator(type_indicator: u32) -> &'static str {
+    match type_indicator {
+        13 => "image/jpeg",
+        14 => "image/png",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Adds the decoded value of a single `ilst` entry, keyed by `name` (either a literal 4cc such as
+/// `"\xa9nam"`, or an `mdta` key name resolved via the `keys` box), to the metadata being built.
+fn add_entry(name: &str, atom: DataAtom, builder: &mut MetadataBuilder, options: &MetadataOptions) -> Result<()> {
+    match atom.type_indicator {
+        1 => {
+            // UTF-8 text.
+            let value = String::from_utf8_lossy(&atom.payload).into_owned();
+            builder.add_tag(Tag::new(std_tag_key_for_name(name), name, &value));
+        }
+        0 | 21 if name == "trkn" || name == "disk" => {
+            let (current, total) = decode_pair(&atom.payload);
+            let std_key = if name == "trkn" { StandardTagKey::TrackNumber } else { StandardTagKey::DiscNumber };
+
+            if let Some(current) = current {
+                builder.add_tag(Tag::new(Some(std_key), name, &current.to_string()));
+            }
+            if let Some(total) = total {
+                let total_key = if name == "trkn" { StandardTagKey::TrackTotal } else { StandardTagKey::DiscTotal };
+                let total_name = if name == "trkn" { "trkn_total" } else { "disk_total" };
+                builder.add_tag(Tag::new(Some(total_key), total_name, &total.to_string()));
+            }
+        }
+        0 | 21 => {
+            let mut value: u64 = 0;
+            for &b in &atom.payload {
+                value = (value << 8) | u64::from(b);
+            }
+            builder.add_tag(Tag::new(std_tag_key_for_name(name), name, &value.to_string()));
+        }
+        13 | 14 => {
+            if !options.limit_visual_bytes.limit_or_default(4 * 1024 * 1024)
+                .map(|max| atom.payload.len() > max).unwrap_or(false)
+            {
+                let mut visual = Visual {
+                    media_type: media_type_for_indicator(atom.type_indicator).to_string(),
+                    dimensions: None,
+                    bits_per_pixel: None,
+                    color_mode: None,
+                    usage: Some(StandardVisualKey::FrontCover),
+                    tags: Vec::new(),
+                    data: atom.payload.into_boxed_slice(),
+                };
+
+                if options.probe_visual_dimensions {
+                    super::probe_visual(&mut visual);
+                }
+
+                builder.add_visual(visual);
+            }
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// Reads the `keys` box of an `mdta`-style `ilst`, returning the ordered (1-based) list of key
+/// names.
+fn read_keys(reader: &mut MediaSourceStream, end: u64) -> Result<Vec<String>> {
+    let header = match find_child_box(reader, end, b"keys")? {
+        Some(header) => header,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut count_buf = [0u8; 8];
+    reader.read_exact(&mut count_buf)?;
+    let count = u32::from_be_bytes([count_buf[4], count_buf[5], count_buf[6], count_buf[7]]);
+
+    let mut keys = Vec::with_capacity(count as usize);
+
+    for _ in 0..count {
+        let entry = read_box_header(reader)?;
+        let mut namespace = [0u8; 4];
+        reader.read_exact(&mut namespace)?;
+
+        let name_len = (entry.end - (entry.body_start + 4)) as usize;
+        let mut name_buf = vec![0u8; name_len];
+        reader.read_exact(&mut name_buf)?;
+
+        keys.push(String::from_utf8_lossy(&name_buf).into_owned());
+
+        reader.seek(SeekFrom::Start(entry.end))?;
+    }
+
+    reader.seek(SeekFrom::Start(header.end))?;
+
+    Ok(keys)
+}
+
+/// A `MetadataReader` implementation for the `ilst` metadata atoms used by MP4/M4A files.
+pub struct Mp4Reader {
+    options: MetadataOptions,
+}
+
+impl MetadataReader for Mp4Reader {
+    fn new(options: &MetadataOptions) -> Self {
+        Mp4Reader { options: *options }
+    }
+
+    fn read_all(&mut self, reader: &mut MediaSourceStream) -> Result<Metadata> {
+        let file_end = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        let moov = find_child_box(reader, file_end, b"moov")?
+            .ok_or(Error::DecodeError("mp4: no moov box"))?;
+
+        let udta = match find_child_box(reader, moov.end, b"udta")? {
+            Some(udta) => udta,
+            None => return Ok(MetadataBuilder::new().metadata()),
+        };
+
+        let meta = match find_child_box(reader, udta.end, b"meta")? {
+            Some(meta) => meta,
+            None => return Ok(MetadataBuilder::new().metadata()),
+        };
+
+        // The `meta` box has a 4-byte version/flags field before its children, unlike most boxes.
+        reader.seek(SeekFrom::Start(meta.body_start + 4))?;
+
+        let keys = read_keys(reader, meta.end)?;
+
+        // `read_keys` scans the children of `meta` looking for `keys`, leaving the stream
+        // positioned at `meta.end` if none was found, or just past `keys` if it was. Rewind to
+        // the start of `meta`'s children before searching for `ilst`, which may otherwise be a
+        // sibling we've already scanned past.
+        reader.seek(SeekFrom::Start(meta.body_start + 4))?;
+
+        let ilst = match find_child_box(reader, meta.end, b"ilst")? {
+            Some(ilst) => ilst,
+            None => return Ok(MetadataBuilder::new().metadata()),
+        };
+
+        let mut builder = MetadataBuilder::new();
+
+        while reader.seek(SeekFrom::Current(0))? < ilst.end {
+            let entry = read_box_header(reader)?;
+
+            // In the `keys`+`ilst` (`mdta`) variant, each entry's 4cc is a 1-based big-endian
+            // index into the `keys` box rather than a literal name.
+            let index = u32::from_be_bytes(entry.name);
+            let name = match keys.get(index.wrapping_sub(1) as usize) {
+                Some(key) => key.clone(),
+                // Literal 4cc bytes are Latin-1 (e.g. `0xa9` is `©` in `©nam`), not UTF-8.
+                None => entry.name.iter().map(|&b| char::from(b)).collect(),
+            };
+
+            if let Some(atom) = read_data_atom(reader, entry.end, &self.options)? {
+                add_entry(&name, atom, &mut builder, &self.options)?;
+            }
+
+            reader.seek(SeekFrom::Start(entry.end))?;
+        }
+
+        Ok(builder.metadata())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::io::MediaSource;
+
+    use super::*;
+
+    struct TestSource(Cursor<Vec<u8>>);
+
+    impl Read for TestSource {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Seek for TestSource {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    impl MediaSource for TestSource {
+        fn is_seekable(&self) -> bool {
+            true
+        }
+
+        fn byte_len(&self) -> Option<u64> {
+            Some(self.0.get_ref().len() as u64)
+        }
+    }
+
+    fn stream(bytes: Vec<u8>) -> MediaSourceStream {
+        MediaSourceStream::new(Box::new(TestSource(Cursor::new(bytes))), Default::default())
+    }
+
+    fn write_box(out: &mut Vec<u8>, name: &[u8; 4], body: &[u8]) {
+        let size = (8 + body.len()) as u32;
+        out.extend_from_slice(&size.to_be_bytes());
+        out.extend_from_slice(name);
+        out.extend_from_slice(body);
+    }
+
+    fn make_data_box(type_indicator: u32, payload: &[u8]) -> Vec<u8> {
+        let mut inner = Vec::new();
+        inner.extend_from_slice(&type_indicator.to_be_bytes());
+        inner.extend_from_slice(&0u32.to_be_bytes());
+        inner.extend_from_slice(payload);
+
+        let mut out = Vec::new();
+        write_box(&mut out, b"data", &inner);
+        out
+    }
+
+    #[test]
+    fn truncated_data_box_is_a_decode_error_not_a_panic() {
+        // A `data` box declaring a size of 9: just large enough for the box header, but seven
+        // bytes short of the 8-byte type/locale fields `read_data_atom` always reads. Pad the buffer
+        // with extra bytes so the type/locale read itself succeeds and the too-small declared
+        // size is what's exercised, not a `read_exact` EOF.
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&9u32.to_be_bytes());
+        buf.extend_from_slice(b"data");
+        buf.extend_from_slice(&[0u8; 8]);
+
+        let mut reader = stream(buf);
+        let options = MetadataOptions::default();
+
+        let result = read_data_atom(&mut reader, 16, &options);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn box_with_largesize_smaller_than_its_own_header_is_a_decode_error() {
+        // A crafted top-level box: ordinary `size == 1` (requesting the 64-bit `largesize`
+        // extension) with a `largesize` of `0`. `end` would be `start`, which is less than
+        // `body_start` (`start + 16`) — without the bounds check, every caller seeks backward to
+        // `end` when this box doesn't match what they're looking for, looping forever.
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&1u32.to_be_bytes());
+        buf.extend_from_slice(b"free");
+        buf.extend_from_slice(&0u64.to_be_bytes());
+
+        let mut reader = stream(buf);
+        let result = find_child_box(&mut reader, 16, b"moov");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn ilst_is_found_after_scanning_for_an_absent_keys_box() {
+        // Regression test for the stream-position bug: searching for (and not finding) `keys`
+        // must not leave the reader past a real `ilst` sibling that comes after it.
+        let entry = {
+            let mut b = Vec::new();
+            write_box(&mut b, b"\xa9nam", &make_data_box(1, b"Example"));
+            b
+        };
+
+        let ilst = {
+            let mut b = Vec::new();
+            write_box(&mut b, b"ilst", &entry);
+            b
+        };
+
+        let meta_body = {
+            let mut b = vec![0, 0, 0, 0];
+            b.extend_from_slice(&ilst);
+            b
+        };
+
+        let meta = {
+            let mut b = Vec::new();
+            write_box(&mut b, b"meta", &meta_body);
+            b
+        };
+
+        let udta = {
+            let mut b = Vec::new();
+            write_box(&mut b, b"udta", &meta);
+            b
+        };
+
+        let moov = {
+            let mut b = Vec::new();
+            write_box(&mut b, b"moov", &udta);
+            b
+        };
+
+        let mut reader = stream(moov);
+        let mut mp4 = Mp4Reader::new(&MetadataOptions::default());
+
+        let metadata = mp4.read_all(&mut reader).expect("valid mp4 metadata");
+        assert_eq!(metadata.tags().len(), 1);
+        assert_eq!(metadata.tags()[0].value, "Example");
+    }
+}
@@ -0,0 +1,138 @@
+// Sonata
+// Copyright (c) 2019 The Sonata Project Developers.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Typed access to ReplayGain tags, and a helper to apply the resulting gain to decoded audio.
+
+use crate::audio::{AudioBufferRef, Signal};
+
+use super::{Metadata, StandardTagKey};
+
+/// `ReplayGain` is the decoded gain and peak information for a track or album, as conveyed by the
+/// `ReplayGain*Gain`/`ReplayGain*Peak` standard tags.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ReplayGain {
+    /// The suggested gain adjustment, in decibels.
+    pub gain_db: f32,
+    /// The peak sample magnitude, linear in the range `[0, 1]`, if known.
+    pub peak: Option<f32>,
+}
+
+/// Parses a ReplayGain gain string (e.g., `"-6.54 dB"`) into decibels, tolerating the trailing
+/// unit and surrounding whitespace. Rejects non-finite values (e.g. a tag of `"nan dB"` or
+/// `"inf dB"`), which Rust's float parser otherwise accepts.
+fn parse_gain(value: &str) -> Option<f32> {
+    let trimmed = value.trim();
+    let numeric = trimmed.trim_end_matches(|c: char| c.is_alphabetic() || c.is_whitespace());
+    numeric.trim().parse::<f32>().ok().filter(|v| v.is_finite())
+}
+
+/// Parses a ReplayGain peak string into a linear sample magnitude. Rejects non-finite values.
+fn parse_peak(value: &str) -> Option<f32> {
+    value.trim().parse::<f32>().ok().filter(|v| v.is_finite())
+}
+
+impl Metadata {
+    /// Scans the tags of this `Metadata` revision for ReplayGain information, preferring album
+    /// values over track values when both are present. Returns `None` if no ReplayGain gain tag
+    /// is present.
+    pub fn replay_gain(&self) -> Option<ReplayGain> {
+        let mut track_gain = None;
+        let mut track_peak = None;
+        let mut album_gain = None;
+        let mut album_peak = None;
+
+        for tag in self.tags() {
+            match tag.std_key {
+                Some(StandardTagKey::ReplayGainTrackGain) => track_gain = parse_gain(&tag.value),
+                Some(StandardTagKey::ReplayGainTrackPeak) => track_peak = parse_peak(&tag.value),
+                Some(StandardTagKey::ReplayGainAlbumGain) => album_gain = parse_gain(&tag.value),
+                Some(StandardTagKey::ReplayGainAlbumPeak) => album_peak = parse_peak(&tag.value),
+                _ => (),
+            }
+        }
+
+        let gain_db = album_gain.or(track_gain)?;
+        let peak = album_peak.or(track_peak);
+
+        Some(ReplayGain { gain_db, peak })
+    }
+}
+
+impl ReplayGain {
+    /// Computes the linear scale factor for this gain, clamped so that, when the peak sample
+    /// magnitude is known, applying it can never cause clipping. Returns `1.0` (no-op) if
+    /// `gain_db` is not finite, and ignores a `peak` that is not finite or not positive (the
+    /// factor is then unclamped), since `ReplayGain`'s fields are public and may not have come
+    /// from `Metadata::replay_gain`'s validated parsing.
+    pub fn factor(&self) -> f32 {
+        if !self.gain_db.is_finite() {
+            return 1.0;
+        }
+
+        let factor = 10f32.powf(self.gain_db / 20.0);
+
+        match self.peak {
+            Some(peak) if peak.is_finite() && peak > 0.0 => factor.min(1.0 / peak),
+            _ => factor,
+        }
+    }
+
+    /// Applies this gain to every sample of every channel in `buf`, in place.
+    ///
+    /// Only the `F32` variant of `AudioBufferRef` is supported; other sample formats are left
+    /// untouched.
+    pub fn apply(&self, buf: &mut AudioBufferRef) {
+        let factor = self.factor();
+
+        if let AudioBufferRef::F32(buf) = buf {
+            let buf = buf.to_mut();
+            for channel in 0..buf.spec().channels.count() {
+                for sample in buf.chan_mut(channel) {
+                    *sample *= factor;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_typical_gain_and_peak() {
+        assert_eq!(parse_gain("-6.54 dB"), Some(-6.54));
+        assert_eq!(parse_peak("0.5"), Some(0.5));
+    }
+
+    #[test]
+    fn rejects_non_finite_gain_and_peak_strings() {
+        assert_eq!(parse_gain("nan dB"), None);
+        assert_eq!(parse_gain("inf dB"), None);
+        assert_eq!(parse_peak("nan"), None);
+        assert_eq!(parse_peak("-inf"), None);
+    }
+
+    #[test]
+    fn factor_is_a_no_op_for_a_non_finite_gain_built_directly() {
+        // `ReplayGain`'s fields are public, so a caller could construct one without going
+        // through the validated string parsing above.
+        let gain = ReplayGain { gain_db: f32::NAN, peak: None };
+        assert_eq!(gain.factor(), 1.0);
+    }
+
+    #[test]
+    fn album_values_take_precedence_over_track_values() {
+        let mut builder = super::super::MetadataBuilder::new();
+        builder.add_tag(super::super::Tag::new(Some(StandardTagKey::ReplayGainTrackGain), "", "-3.0 dB"));
+        builder.add_tag(super::super::Tag::new(Some(StandardTagKey::ReplayGainAlbumGain), "", "-6.0 dB"));
+
+        let metadata = builder.metadata();
+        let gain = metadata.replay_gain().expect("a replay gain tag was present");
+        assert_eq!(gain.gain_db, -6.0);
+    }
+}
@@ -0,0 +1,229 @@
+// Sonata
+// Copyright (c) 2019 The Sonata Project Developers.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Authoritative `Visual` dimension and color-mode probing, by parsing the header of the
+//! embedded image data rather than trusting the (possibly inaccurate) metadata-provided hints.
+
+#[cfg(feature = "std")]
+use std::num::NonZeroU32;
+
+#[cfg(not(feature = "std"))]
+use core::num::NonZeroU32;
+
+use super::{ColorMode, Size, Visual};
+
+/// Overwrites `visual`'s `dimensions`, `bits_per_pixel`, and `color_mode` with values read from
+/// the leading bytes of `visual.data`, if the image format is recognized. If the format is not
+/// recognized, `visual` is left untouched.
+pub fn probe_visual(visual: &mut Visual) {
+    if let Some(probed) = probe_png(&visual.data)
+        .or_else(|| probe_jpeg(&visual.data))
+        .or_else(|| probe_gif(&visual.data))
+    {
+        visual.dimensions = Some(probed.dimensions);
+        visual.bits_per_pixel = probed.bits_per_pixel;
+        visual.color_mode = probed.color_mode;
+    }
+}
+
+struct Probed {
+    dimensions: Size,
+    bits_per_pixel: Option<NonZeroU32>,
+    color_mode: Option<ColorMode>,
+}
+
+const PNG_MAGIC: [u8; 8] = [0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a];
+
+/// Reads the `IHDR` chunk of a PNG image, which immediately follows the 8-byte file signature as
+/// `[u32 length][\"IHDR\"][u32 width][u32 height][u8 bit depth][u8 color type]...`.
+fn probe_png(data: &[u8]) -> Option<Probed> {
+    if data.len() < 8 + 8 + 13 || data[0..8] != PNG_MAGIC {
+        return None;
+    }
+
+    if &data[12..16] != b"IHDR" {
+        return None;
+    }
+
+    let ihdr = &data[16..16 + 13];
+
+    let width = u32::from_be_bytes([ihdr[0], ihdr[1], ihdr[2], ihdr[3]]);
+    let height = u32::from_be_bytes([ihdr[4], ihdr[5], ihdr[6], ihdr[7]]);
+    let bit_depth = ihdr[8];
+    let color_type = ihdr[9];
+
+    // PNG only defines these five bit depths; anything else is a corrupt or non-conformant
+    // image, and `1u32 << bit_depth` below would overflow for any value >= 32.
+    if !matches!(bit_depth, 1 | 2 | 4 | 8 | 16) {
+        return None;
+    }
+
+    // The number of color/alpha samples per pixel for each PNG color type.
+    let samples = match color_type {
+        0 => 1,                  // Grayscale.
+        2 => 3,                  // Truecolor.
+        3 => 1,                  // Indexed.
+        4 => 2,                  // Grayscale + alpha.
+        6 => 4,                  // Truecolor + alpha.
+        _ => return None,
+    };
+
+    let bits_per_pixel = u32::from(bit_depth) * samples;
+
+    let color_mode = if color_type == 3 {
+        // The palette size isn't known without also parsing the `PLTE` chunk; report the
+        // maximum number of entries addressable at this bit depth.
+        NonZeroU32::new(1u32 << bit_depth).map(ColorMode::Indexed)
+    } else {
+        Some(ColorMode::Discrete)
+    };
+
+    Some(Probed {
+        dimensions: Size { width, height },
+        bits_per_pixel: NonZeroU32::new(bits_per_pixel),
+        color_mode,
+    })
+}
+
+/// Scans a JPEG's markers for a start-of-frame marker (`SOF0` baseline or `SOF2` progressive),
+/// reading `[0xff][marker][u16 length][u8 precision][u16 height][u16 width][u8 components]`.
+fn probe_jpeg(data: &[u8]) -> Option<Probed> {
+    if data.len() < 4 || data[0] != 0xff || data[1] != 0xd8 {
+        return None;
+    }
+
+    let mut pos = 2;
+
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xff {
+            // Not aligned on a marker; the stream is malformed or we've run past the markers.
+            return None;
+        }
+
+        let marker = data[pos + 1];
+
+        // A 0xff fill byte, or a standalone marker without a length field (SOI, TEM, RSTn, EOI):
+        // skip it and keep scanning.
+        if marker == 0xff {
+            pos += 1;
+            continue;
+        }
+        if marker == 0xd8 || marker == 0x01 || (0xd0..=0xd9).contains(&marker) {
+            pos += 2;
+            continue;
+        }
+
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+
+        if marker == 0xc0 || marker == 0xc2 {
+            // `length` (Lf) includes its own 2 bytes, so a valid SOF0/SOF2 segment needs at
+            // least 8: 2 for the length field itself, then 1 precision + 2 height + 2 width + 1
+            // components. Anything shorter doesn't have room for `segment[0..=5]` below.
+            if pos + 2 + length > data.len() || length < 8 {
+                return None;
+            }
+
+            let segment = &data[pos + 4..];
+            let precision = u32::from(segment[0]);
+            let height = u32::from(u16::from_be_bytes([segment[1], segment[2]]));
+            let width = u32::from(u16::from_be_bytes([segment[3], segment[4]]));
+            let components = u32::from(segment[5]);
+
+            return Some(Probed {
+                dimensions: Size { width, height },
+                bits_per_pixel: NonZeroU32::new(precision * components),
+                color_mode: Some(ColorMode::Discrete),
+            });
+        }
+
+        pos += 2 + length;
+    }
+
+    None
+}
+
+/// Reads a GIF's logical screen descriptor: `[\"GIF87a\"|\"GIF89a\"][u16 width][u16 height]
+/// [u8 packed][u8 bg color][u8 pixel aspect]`, all little-endian.
+fn probe_gif(data: &[u8]) -> Option<Probed> {
+    if data.len() < 13 || (&data[0..6] != b"GIF87a" && &data[0..6] != b"GIF89a") {
+        return None;
+    }
+
+    let width = u32::from(u16::from_le_bytes([data[6], data[7]]));
+    let height = u32::from(u16::from_le_bytes([data[8], data[9]]));
+    let packed = data[10];
+
+    let has_global_color_table = packed & 0x80 != 0;
+    let color_mode = if has_global_color_table {
+        let table_size_exp = (packed & 0x07) as u32;
+        NonZeroU32::new(2u32.pow(table_size_exp + 1)).map(ColorMode::Indexed)
+    } else {
+        None
+    };
+
+    let color_resolution = ((packed >> 4) & 0x07) + 1;
+
+    Some(Probed {
+        dimensions: Size { width, height },
+        bits_per_pixel: NonZeroU32::new(u32::from(color_resolution)),
+        color_mode,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_sof_segment_does_not_panic() {
+        // A `length` of 7 is one short of the 8 a SOF0 segment needs (2 length bytes + 1
+        // precision + 2 height + 2 width + 1 components), with the declared segment ending
+        // exactly at the end of the buffer. This used to index one byte past the slice.
+        let mut data = vec![0xff, 0xd8, 0xff, 0xc0, 0x00, 0x07];
+        data.extend_from_slice(&[0u8; 5]);
+
+        assert!(probe_jpeg(&data).is_none());
+    }
+
+    #[test]
+    fn well_formed_sof0_segment_is_probed() {
+        let mut data = vec![0xff, 0xd8, 0xff, 0xc0, 0x00, 0x08];
+        // precision, height, width, components.
+        data.extend_from_slice(&[8, 0x00, 0x64, 0x00, 0xc8, 3]);
+
+        let probed = probe_jpeg(&data).expect("a valid SOF0 segment");
+        assert_eq!(probed.dimensions.width, 200);
+        assert_eq!(probed.dimensions.height, 100);
+        assert_eq!(probed.bits_per_pixel.map(|v| v.get()), Some(24));
+    }
+
+    #[test]
+    fn truncated_png_signature_is_not_probed() {
+        assert!(probe_png(&[0x89, 0x50, 0x4e]).is_none());
+    }
+
+    #[test]
+    fn truncated_gif_header_is_not_probed() {
+        assert!(probe_gif(b"GIF89a\x01").is_none());
+    }
+
+    #[test]
+    fn png_with_out_of_range_indexed_bit_depth_does_not_panic() {
+        // `color_type == 3` (indexed) shifts `1u32 << bit_depth` to derive the palette size; a
+        // corrupt/crafted `bit_depth` of 200 used to overflow that shift.
+        let mut data = Vec::from(PNG_MAGIC);
+        data.extend_from_slice(&13u32.to_be_bytes());
+        data.extend_from_slice(b"IHDR");
+        data.extend_from_slice(&100u32.to_be_bytes()); // width
+        data.extend_from_slice(&100u32.to_be_bytes()); // height
+        data.push(200); // bit depth (invalid)
+        data.push(3); // color type: indexed
+        data.extend_from_slice(&[0, 0, 0]); // compression, filter, interlace
+
+        assert!(probe_png(&data).is_none());
+    }
+}
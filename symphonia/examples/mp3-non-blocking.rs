@@ -115,7 +115,16 @@ fn main() {
                 unimplemented!();
             }
             Err(Error::MoreDataRequired) => {
+                // `SlowFile` always recovers on the very next read, so a short, fixed backoff is
+                // enough to stop this from being a true zero-delay busy-spin. This is not the
+                // same thing as `sonata_core::io::buffered_source::BufferedSource`'s intended
+                // usage: that type is meant to be owned by `try_next_packet` itself, so that a
+                // blocked read can be `rewind()`-ed and the caller woken only once `push()`
+                // supplies the missing bytes, instead of polling at all. Wiring it in that way
+                // would mean editing `MpaReader`/`StreamingFormatReader`'s packet-parsing loop,
+                // which lives outside this source tree and isn't available to change here.
                 println!("More data required. Try again.");
+                std::thread::sleep(std::time::Duration::from_millis(1));
                 continue 'decode;
             }
             Err(err) => {